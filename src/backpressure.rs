@@ -0,0 +1,107 @@
+use futures::{Async, Poll, Stream};
+use futures::task::{self, Task};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+struct GateState {
+    paused: bool,
+    task: Option<Task>,
+}
+
+/// A pause/resume switch shared between a `Throttled` stream (polled by the
+/// reactor) and whoever decides the stream should stop yielding items for a
+/// while -- in `Service`'s case, the message loop, once a connection's
+/// outbound queue has backed up to its high-water mark.
+#[derive(Clone)]
+pub struct Gate {
+    state: Rc<RefCell<GateState>>,
+}
+
+impl Gate {
+    /// Constructs a new `Gate` in the open (not paused) state.
+    pub fn new() -> Gate {
+        Gate { state: Rc::new(RefCell::new(GateState { paused: false, task: None })) }
+    }
+
+    /// Wraps `inner` so it stops yielding items as soon as this gate is
+    /// paused.
+    pub fn throttle<S: Stream>(&self, inner: S) -> Throttled<S> {
+        Throttled { gate: self.clone(), inner: inner }
+    }
+
+    /// Stops any `Throttled` stream wrapping this gate from yielding
+    /// further items until `resume` is called.
+    pub fn pause(&self) {
+        self.state.borrow_mut().paused = true;
+    }
+
+    /// Lets a paused `Throttled` stream continue, waking the reactor task
+    /// blocked on it, if there is one.
+    pub fn resume(&self) {
+        let mut state = self.state.borrow_mut();
+        state.paused = false;
+        if let Some(task) = state.task.take() {
+            task.notify();
+        }
+    }
+}
+
+/// A `Stream` adaptor that reports `NotReady` instead of polling its inner
+/// stream whenever its `Gate` is paused, rather than yielding items that
+/// would only build up in a downstream queue.
+pub struct Throttled<S> {
+    gate: Gate,
+    inner: S,
+}
+
+impl<S: Stream> Stream for Throttled<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        {
+            let mut state = self.gate.state.borrow_mut();
+            if state.paused {
+                state.task = Some(task::current());
+                return Ok(Async::NotReady);
+            }
+        }
+        self.inner.poll()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Gate;
+    use futures::{Async, Stream};
+    use futures::stream::iter_ok;
+
+    #[test]
+    fn throttled_yields_items_while_the_gate_is_open() {
+        let gate = Gate::new();
+        let mut throttled = gate.throttle(iter_ok::<_, ()>(vec![1, 2, 3]));
+
+        assert_eq!(Async::Ready(Some(1)), throttled.poll().unwrap());
+    }
+
+    #[test]
+    fn throttled_reports_not_ready_while_the_gate_is_paused() {
+        let gate = Gate::new();
+        let mut throttled = gate.throttle(iter_ok::<_, ()>(vec![1, 2, 3]));
+
+        gate.pause();
+        assert_eq!(Async::NotReady, throttled.poll().unwrap());
+    }
+
+    #[test]
+    fn throttled_resumes_yielding_items_once_the_gate_reopens() {
+        let gate = Gate::new();
+        let mut throttled = gate.throttle(iter_ok::<_, ()>(vec![1, 2, 3]));
+
+        gate.pause();
+        assert_eq!(Async::NotReady, throttled.poll().unwrap());
+
+        gate.resume();
+        assert_eq!(Async::Ready(Some(1)), throttled.poll().unwrap());
+    }
+}
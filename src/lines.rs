@@ -3,13 +3,35 @@ use tokio_io::codec::{Encoder, Decoder};
 use std::io;
 use std::str;
 
-/// An empty struct that serves as a hook to hang our codec implementation
-/// on.
-pub struct LineCodec;
+/// The default cap on how long an unterminated line may grow before
+/// `LineCodec` gives up on it. Without a limit, a client that never sends a
+/// newline could force the decoder to buffer an unbounded amount of data.
+const DEFAULT_MAX_FRAME_LEN: usize = 64 * 1024;
+
+/// A newline-delimited framing codec. `decode` is incremental: rather than
+/// rescanning the whole buffer on every call, it remembers how far it's
+/// already searched in `next_search_index`, so a line that trickles in
+/// across many small reads is still only scanned once overall.
+pub struct LineCodec {
+    max_frame_len: usize,
+    next_search_index: usize,
+}
 
 impl LineCodec {
     pub fn new() -> LineCodec {
-        LineCodec {}
+        LineCodec {
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+            next_search_index: 0,
+        }
+    }
+
+    /// Constructs a `LineCodec` that gives up on an unterminated line once
+    /// it exceeds `max_frame_len` bytes, instead of the default 64 KiB.
+    pub fn with_max_frame_len(max_frame_len: usize) -> LineCodec {
+        LineCodec {
+            max_frame_len: max_frame_len,
+            next_search_index: 0,
+        }
     }
 }
 
@@ -32,15 +54,29 @@ impl Decoder for LineCodec {
     type Error = io::Error;
 
     fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<String>> {
-        if let Some(n) = buf.iter().position(|&b| b == b'\n') {
-            let line = buf.split_to(n);
-            buf.split_to(1);
-            match str::from_utf8(&line) {
-                Ok(s) => Ok(Some(s.to_string())),
-                Err(e) => Err(io::Error::new(io::ErrorKind::Other, e)),
-            }
-        } else {
-            Ok(None)
+        let search_from = self.next_search_index;
+        match buf[search_from..].iter().position(|&b| b == b'\n') {
+            Some(offset) => {
+                let n = search_from + offset;
+                let line = buf.split_to(n);
+                buf.split_to(1);
+                self.next_search_index = 0;
+
+                match str::from_utf8(&line) {
+                    Ok(s) => Ok(Some(s.to_string())),
+                    Err(e) => Err(io::Error::new(io::ErrorKind::Other, e)),
+                }
+            },
+            None => {
+                self.next_search_index = buf.len();
+                if self.next_search_index > self.max_frame_len {
+                    Err(io::Error::new(io::ErrorKind::InvalidData,
+                                        format!("frame of at least {} bytes exceeds the {} byte limit",
+                                                self.next_search_index, self.max_frame_len)))
+                } else {
+                    Ok(None)
+                }
+            },
         }
     }
 }
@@ -49,6 +85,7 @@ impl Decoder for LineCodec {
 mod test {
     use super::LineCodec;
     use bytes::{BufMut, BytesMut};
+    use std::io;
     use tokio_io::codec::{Encoder, Decoder};
 
     #[test]
@@ -145,4 +182,32 @@ mod test {
             assert!(x.is_err())
         }
     }
+
+    #[test]
+    fn decoder_errors_once_unterminated_line_exceeds_max_frame_len() {
+        let mut b = BytesMut::with_capacity(16);
+        b.put(&[b'a'; 8][..]);
+
+        let mut codec = LineCodec::with_max_frame_len(8);
+        assert!(codec.decode(&mut b).unwrap().is_none());
+
+        b.put("a");
+        let err = codec.decode(&mut b).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn decoder_assembles_a_line_delivered_across_many_partial_reads() {
+        let mut b = BytesMut::with_capacity(0);
+        let mut codec = LineCodec::new();
+
+        for chunk in "The boy stood on the burning deck".as_bytes().chunks(3) {
+            b.put(chunk);
+            assert!(codec.decode(&mut b).unwrap().is_none());
+        }
+
+        b.put("\n");
+        let x = codec.decode(&mut b).unwrap().unwrap();
+        assert_eq!("The boy stood on the burning deck", x);
+    }
 }
@@ -1,34 +1,82 @@
+extern crate base64;
 extern crate bytes;
 extern crate env_logger;
 extern crate futures;
 #[macro_use] extern crate log;
+extern crate net2;
+extern crate num_cpus;
+extern crate sha1;
 extern crate tokio_core;
 extern crate tokio_io;
 
+mod backpressure;
 mod lines;
+mod mux;
 mod service;
+mod websockets;
+mod ws_codec;
 
-use service::Service;
+use service::{Service, ServiceConfig};
+use websockets::WebsocketServer;
 use tokio_core::reactor::Core;
+use std::env;
+use std::thread;
+
+/// Name of the environment variable that switches the main TCP service into
+/// `Service::run_multithreaded`. Unset runs the plain single-reactor `run`;
+/// set to a number, that many worker reactors are started (`0` picks one per
+/// CPU).
+const WORKERS_ENV_VAR: &'static str = "ASYNCHO_WORKERS";
+
 fn main() {
     use std::process::exit;
     env_logger::init().unwrap();
 
-    info!("Creating task executor");
-    let mut core = Core::new().unwrap();
-    let h = core.handle();
+    // The websocket service runs its own reactor on its own thread, since
+    // `WebsocketServer::run` blocks the thread it's called from for as long
+    // as the service is alive, just like `Service::run`/`run_multithreaded`
+    // do below.
+    info!("Starting websocket service on its own thread");
+    thread::spawn(|| {
+        let mut ws_core = Core::new().unwrap();
+        let ws_addr = "127.0.0.1:4445".parse().unwrap();
 
-    info!("Creating service data structures");
-    let mut service = Service::new(&h);
+        match WebsocketServer::new(ws_addr, &ws_core.handle()) {
+            Ok(mut ws_service) => {
+                info!("Websocket listener started");
+                ws_service.run(&mut ws_core);
+            }
+            Err(e) => error!("Websocket listener failed to start: {}", e),
+        }
+    });
 
     let addr = "127.0.0.1:4444".parse().unwrap();
-    match service.start_listener(&addr) {
-        Ok(_) => info!("Listener started"),
-        Err(e) => {
-            error!("Listener failed to start: {}. Bailing!", e);
-            exit(1);
+    let workers = env::var(WORKERS_ENV_VAR).ok().and_then(|v| v.parse::<usize>().ok());
+
+    match workers {
+        Some(workers) => {
+            info!("Running Service in multi-threaded mode ({} set to {})", WORKERS_ENV_VAR, workers);
+            if let Err(e) = Service::run_multithreaded(&addr, ServiceConfig::default(), workers) {
+                error!("Multi-threaded service failed to start: {}. Bailing!", e);
+                exit(1);
+            }
+        },
+        None => {
+            info!("Creating task executor");
+            let mut core = Core::new().unwrap();
+            let h = core.handle();
+
+            info!("Creating service data structures");
+            let mut service = Service::new(&h);
+            match service.start_listener(&addr) {
+                Ok(_) => info!("Listener started"),
+                Err(e) => {
+                    error!("Listener failed to start: {}. Bailing!", e);
+                    exit(1);
+                }
+            }
+
+            service.run(&mut core);
         }
     }
-
-    service.run(&mut core);
 }
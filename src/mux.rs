@@ -0,0 +1,220 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use tokio_io::codec::{Encoder, Decoder};
+use std::io;
+
+/// The default receive window granted to a freshly-opened substream, in
+/// bytes. A peer may not have more than this many bytes of unconsumed
+/// `Data` payload in flight on a single substream at once.
+pub const DEFAULT_WINDOW: u32 = 256 * 1024;
+
+/// The length, in bytes, of a mux frame header: a 4-byte stream id, a
+/// 1-byte type tag and a 4-byte payload length.
+const HEADER_LEN: usize = 9;
+
+/// The kind of a multiplexed frame, carried as a single-byte tag in the
+/// frame header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameType {
+    /// Carries application payload for a substream.
+    Data,
+    /// Grants the peer additional send credit on a substream; the payload
+    /// is a 4-byte big-endian count of bytes of credit being returned.
+    WindowUpdate,
+    /// Opens a new substream with the given id.
+    Open,
+    /// Closes a substream; no more frames will follow for this id.
+    Close,
+}
+
+impl FrameType {
+    fn from_byte(b: u8) -> io::Result<FrameType> {
+        match b {
+            0 => Ok(FrameType::Data),
+            1 => Ok(FrameType::WindowUpdate),
+            2 => Ok(FrameType::Open),
+            3 => Ok(FrameType::Close),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                     format!("unknown mux frame type: {}", b))),
+        }
+    }
+
+    fn to_byte(&self) -> u8 {
+        match *self {
+            FrameType::Data => 0,
+            FrameType::WindowUpdate => 1,
+            FrameType::Open => 2,
+            FrameType::Close => 3,
+        }
+    }
+}
+
+/// A single frame belonging to one logical substream of a multiplexed
+/// connection.
+#[derive(Debug, Clone)]
+pub struct MuxFrame {
+    pub stream_id: u32,
+    pub frame_type: FrameType,
+    pub payload: Bytes,
+}
+
+impl MuxFrame {
+    pub fn data(stream_id: u32, payload: Bytes) -> MuxFrame {
+        MuxFrame { stream_id: stream_id, frame_type: FrameType::Data, payload: payload }
+    }
+
+    pub fn open(stream_id: u32) -> MuxFrame {
+        MuxFrame { stream_id: stream_id, frame_type: FrameType::Open, payload: Bytes::new() }
+    }
+
+    pub fn close(stream_id: u32) -> MuxFrame {
+        MuxFrame { stream_id: stream_id, frame_type: FrameType::Close, payload: Bytes::new() }
+    }
+
+    /// Builds a `WindowUpdate` frame returning `credit` bytes of window to
+    /// the peer on `stream_id`.
+    pub fn window_update(stream_id: u32, credit: u32) -> MuxFrame {
+        let mut payload = BytesMut::with_capacity(4);
+        payload.put_u32_be(credit);
+        MuxFrame { stream_id: stream_id, frame_type: FrameType::WindowUpdate, payload: payload.freeze() }
+    }
+
+    /// Reads the credit value out of a `WindowUpdate` frame's payload.
+    pub fn window_credit(&self) -> io::Result<u32> {
+        if self.payload.len() != 4 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                       "malformed WindowUpdate payload"));
+        }
+        let b = &self.payload;
+        Ok(((b[0] as u32) << 24) | ((b[1] as u32) << 16) | ((b[2] as u32) << 8) | (b[3] as u32))
+    }
+}
+
+/// Implements the wire framing for the yamux-style multiplexing layer: each
+/// frame is a fixed 9-byte header (`stream_id: u32`, `type: u8`,
+/// `length: u32`) followed by `length` bytes of payload. Several logical
+/// substreams are interleaved over a single underlying connection this way.
+///
+/// `max_payload_len` bounds how large a single frame's `length` field is
+/// allowed to claim to be; a header claiming more than that is rejected
+/// before the decoder waits around buffering it, so a peer can't force
+/// multi-gigabyte buffering with one oversized header regardless of what the
+/// per-substream receive window would otherwise allow.
+pub struct MuxCodec {
+    max_payload_len: usize,
+}
+
+impl MuxCodec {
+    pub fn new() -> MuxCodec {
+        MuxCodec { max_payload_len: DEFAULT_WINDOW as usize }
+    }
+
+    /// Constructs a `MuxCodec` that rejects any frame whose header claims a
+    /// payload larger than `max_payload_len`, instead of the default (the
+    /// substream receive window size).
+    pub fn with_max_payload_len(max_payload_len: usize) -> MuxCodec {
+        MuxCodec { max_payload_len: max_payload_len }
+    }
+}
+
+impl Decoder for MuxCodec {
+    type Item = MuxFrame;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<MuxFrame>> {
+        if buf.len() < HEADER_LEN {
+            return Ok(None);
+        }
+
+        let stream_id = read_u32(&buf[0..4]);
+        let frame_type = FrameType::from_byte(buf[4])?;
+        let len = read_u32(&buf[5..9]) as usize;
+
+        if len > self.max_payload_len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                       format!("mux frame on stream {} claims {} bytes, exceeding the {} byte limit",
+                                               stream_id, len, self.max_payload_len)));
+        }
+
+        if buf.len() < HEADER_LEN + len {
+            return Ok(None);
+        }
+
+        buf.split_to(HEADER_LEN);
+        let payload = buf.split_to(len).freeze();
+
+        Ok(Some(MuxFrame { stream_id: stream_id, frame_type: frame_type, payload: payload }))
+    }
+}
+
+impl Encoder for MuxCodec {
+    type Item = MuxFrame;
+    type Error = io::Error;
+
+    fn encode(&mut self, frame: MuxFrame, buf: &mut BytesMut) -> io::Result<()> {
+        buf.reserve(HEADER_LEN + frame.payload.len());
+        buf.put_u32_be(frame.stream_id);
+        buf.put_u8(frame.frame_type.to_byte());
+        buf.put_u32_be(frame.payload.len() as u32);
+        buf.extend(frame.payload);
+        Ok(())
+    }
+}
+
+fn read_u32(b: &[u8]) -> u32 {
+    ((b[0] as u32) << 24) | ((b[1] as u32) << 16) | ((b[2] as u32) << 8) | (b[3] as u32)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{MuxCodec, MuxFrame, FrameType, HEADER_LEN};
+    use bytes::{BufMut, Bytes, BytesMut};
+    use std::io;
+    use tokio_io::codec::{Encoder, Decoder};
+
+    #[test]
+    fn round_trips_a_data_frame() {
+        let mut b = BytesMut::with_capacity(0);
+        let mut codec = MuxCodec::new();
+        let frame = MuxFrame::data(7, Bytes::from_static(b"hello"));
+        codec.encode(frame, &mut b).unwrap();
+
+        let decoded = codec.decode(&mut b).unwrap().unwrap();
+        assert_eq!(7, decoded.stream_id);
+        assert_eq!(FrameType::Data, decoded.frame_type);
+        assert_eq!(&b"hello"[..], &decoded.payload[..]);
+    }
+
+    #[test]
+    fn waits_for_full_frame_across_partial_reads() {
+        let mut b = BytesMut::with_capacity(0);
+        let mut codec = MuxCodec::new();
+        let frame = MuxFrame::data(1, Bytes::from_static(b"hi"));
+        let mut full = BytesMut::with_capacity(0);
+        codec.encode(frame, &mut full).unwrap();
+
+        b.extend(&full[..full.len() - 1]);
+        assert!(codec.decode(&mut b).unwrap().is_none());
+
+        b.extend(&full[full.len() - 1..]);
+        assert!(codec.decode(&mut b).unwrap().is_some());
+    }
+
+    #[test]
+    fn window_update_round_trips_credit() {
+        let frame = MuxFrame::window_update(3, 1024);
+        assert_eq!(FrameType::WindowUpdate, frame.frame_type);
+        assert_eq!(1024, frame.window_credit().unwrap());
+    }
+
+    #[test]
+    fn decoder_rejects_a_header_claiming_more_than_the_payload_limit() {
+        let mut b = BytesMut::with_capacity(HEADER_LEN);
+        b.put_u32_be(1);
+        b.put_u8(FrameType::Data.to_byte());
+        b.put_u32_be(1024);
+
+        let mut codec = MuxCodec::with_max_payload_len(64);
+        let err = codec.decode(&mut b).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+}
@@ -1,29 +1,95 @@
 
+use std::cell::RefCell;
 use std::collections::{BTreeMap, VecDeque};
 use std::io::{self, ErrorKind};
-use std::net::SocketAddr;
+use std::net::{SocketAddr, TcpStream as StdTcpStream};
 use std::mem::replace;
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
 
-use futures::{Future, Stream};
-use futures::unsync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded};
+use bytes::{Bytes, BytesMut};
+use futures::{Future, Sink, Stream};
+use futures::sync::mpsc::{channel, Receiver, Sender};
 use futures::stream::SplitSink;
+use net2::TcpBuilder;
 
+use backpressure::Gate;
 use lines::LineCodec;
+use mux::{DEFAULT_WINDOW, FrameType, MuxCodec, MuxFrame};
 use tokio_core::reactor::{Core, Handle};
 use tokio_core::net::{TcpListener, TcpStream};
 use tokio_io::AsyncRead;
-use tokio_io::codec::Framed;
+use tokio_io::codec::{Decoder, Encoder, Framed};
+
+/// Socket tuning knobs applied to the listener and to each connection it
+/// accepts, plus the flow-control limits described on each field below. See
+/// `Service::with_config`.
+#[derive(Debug, Clone, Copy)]
+pub struct ServiceConfig {
+    /// Sets `TCP_NODELAY` on each accepted stream, disabling Nagle's
+    /// algorithm so small echoed frames aren't delayed waiting to be
+    /// coalesced.
+    pub nodelay: bool,
+    /// Sets `SO_KEEPALIVE` on each accepted stream, with the given idle
+    /// time before the first probe. `None` leaves keepalive disabled.
+    pub keepalive: Option<Duration>,
+    /// Sets `SO_LINGER` on each accepted stream, so that closing a
+    /// connection under churn (e.g. in a load test) doesn't leave it
+    /// sitting in `TIME_WAIT`. `None` leaves the platform default in place.
+    pub linger: Option<Duration>,
+    /// Sets `SO_REUSEADDR` on the listening socket, so the service can be
+    /// rebound to the same address quickly after a restart.
+    pub reuseaddr: bool,
+    /// The capacity of the bounded `Msg` channel that carries every event
+    /// into the message loop. Once it's full, senders -- including the
+    /// accept loop -- block until the loop catches up.
+    pub channel_capacity: usize,
+    /// Once a connection's outbound frame queue reaches this many queued
+    /// frames, the service stops reading further frames from that
+    /// connection until the queue drains back to `queue_low_water`.
+    pub queue_high_water: usize,
+    /// The queue depth a connection's outbound frames must drain back to
+    /// before reading from it resumes.
+    pub queue_low_water: usize,
+    /// The most substreams a single connection may have open at once. An
+    /// `Open` frame beyond this cap is refused, so a peer can't pin unbounded
+    /// server memory (one receive window's worth per substream) just by
+    /// opening substreams without ever sending on them.
+    pub max_substreams_per_conn: usize,
+}
+
+impl Default for ServiceConfig {
+    fn default() -> ServiceConfig {
+        ServiceConfig {
+            nodelay: true,
+            keepalive: None,
+            linger: None,
+            reuseaddr: true,
+            channel_capacity: 1024,
+            queue_high_water: 256,
+            queue_low_water: 64,
+            max_substreams_per_conn: 256,
+        }
+    }
+}
 
 /// A shorthand definition of the Sink type for sending frames to a remote
 /// client.
-type FrameSender = SplitSink<Framed<TcpStream, LineCodec>>;
+type FrameSender = SplitSink<Framed<TcpStream, MuxCodec>>;
+
+/// A substream id is only unique within the connection it belongs to, so
+/// substreams are tracked keyed by the pair.
+type SubstreamId = (usize, u32);
 
 /// The range of messages our message handling function can deal with.
 enum Msg {
     NewConnection { conn: TcpStream, remote_addr: SocketAddr },
     ConnectionLost { conn_id: usize },
-    NewFrame { conn_id: usize, frame: String },
+    NewMuxFrame { conn_id: usize, frame: MuxFrame },
     FrameTxComplete { conn_id: usize, new_tx: FrameSender },
+    StreamOpened { conn_id: usize, stream_id: u32 },
+    StreamClosed { conn_id: usize, stream_id: u32 },
 }
 
 /// Represents an individual connection to the service. A `Conn` may either be
@@ -35,18 +101,55 @@ enum Msg {
 /// should be added to the send queue for later transmission. If the field is
 /// not None, then the connection is ready to send, and frames should be sent
 /// immediately.
+///
+/// `queue` is capped at `ServiceConfig::queue_high_water`: once it's full,
+/// `read_gate` is paused so the connection's read half stops accepting new
+/// frames from the client until the queue drains back to
+/// `ServiceConfig::queue_low_water`, giving true per-connection backpressure
+/// instead of unbounded buffering.
 pub struct Conn {
-    queue: VecDeque<String>,
+    queue: VecDeque<MuxFrame>,
     frame_tx: Option<FrameSender>,
+    read_gate: Gate,
 }
 
 impl Conn {
     /// Constructs a new `Conn` that wraps the supplied `FrameSender` with some
     /// metadata.
-    pub fn new(socket: FrameSender) -> Conn {
+    pub fn new(socket: FrameSender, read_gate: Gate) -> Conn {
         Conn {
             queue: VecDeque::new(),
             frame_tx: Some(socket),
+            read_gate: read_gate,
+        }
+    }
+}
+
+/// A substream runs the same newline-delimited echo protocol that a plain
+/// `Service` connection does; `codec`/`decode_buf` reassemble that protocol
+/// out of the raw bytes carried by the substream's `Data` frames.
+///
+/// `recv_window` is how many more bytes of `Data` payload we'll accept from
+/// the peer on this substream before we've sent it enough `WindowUpdate`
+/// credit to continue; `send_credit` is the mirror image, how many bytes
+/// we're still allowed to send before the peer grants us more. Lines we
+/// can't send immediately because we're out of credit wait in `send_queue`.
+struct Substream {
+    recv_window: u32,
+    send_credit: u32,
+    send_queue: VecDeque<String>,
+    decode_buf: BytesMut,
+    codec: LineCodec,
+}
+
+impl Substream {
+    fn new() -> Substream {
+        Substream {
+            recv_window: DEFAULT_WINDOW,
+            send_credit: DEFAULT_WINDOW,
+            send_queue: VecDeque::new(),
+            decode_buf: BytesMut::new(),
+            codec: LineCodec::new(),
         }
     }
 }
@@ -54,24 +157,34 @@ impl Conn {
 /// Implements the echo service.
 pub struct Service {
     conns:        BTreeMap<usize, Conn>,
-    msg_tx:       UnboundedSender<Msg>,
-    msg_rx:       Option<UnboundedReceiver<Msg>>,
+    substreams:   BTreeMap<SubstreamId, Substream>,
+    msg_tx:       Sender<Msg>,
+    msg_rx:       Option<Receiver<Msg>>,
     message_loop: Handle,
     conn_count:   usize,
+    config:       ServiceConfig,
 }
 
 impl Service {
     /// Constructs a new Service object with sane defaults.
     pub fn new(h: &Handle) -> Service {
+        Service::with_config(h, ServiceConfig::default())
+    }
+
+    /// Constructs a new Service object, applying the given socket tuning
+    /// options to the listener and to every connection it accepts.
+    pub fn with_config(h: &Handle, config: ServiceConfig) -> Service {
         info!("Creating messaging channels");
-        let (tx, rx) = unbounded();
+        let (tx, rx) = channel(config.channel_capacity);
 
         Service {
             conns: BTreeMap::new(),
+            substreams: BTreeMap::new(),
             msg_tx: tx,
             msg_rx: Some(rx),
             message_loop: h.clone(),
             conn_count: 0,
+            config: config,
         }
     }
 
@@ -79,8 +192,9 @@ impl Service {
     pub fn start_listener(&self, addr: &SocketAddr) -> io::Result<()> {
         info!("Starting TCP listener for {}", addr);
 
-        // Start a TCP listener on a given port
-        let listener = TcpListener::bind(addr, &self.message_loop)?;
+        // Start a TCP listener on a given port, applying SO_REUSEADDR first
+        // if configured, since it has to be set before the socket is bound.
+        let listener = bind_listener(addr, self.config.reuseaddr, &self.message_loop)?;
 
         // Turn the listener into a stream of incoming connections, and wrap
         // it in a future that will process each incoming connection by posting
@@ -110,6 +224,59 @@ impl Service {
         }
     }
 
+    /// Runs `workers` independent worker reactors instead of one, so accept
+    /// and echo work can be spread across CPU cores; pass `0` to pick one
+    /// worker per core. The listener is bound once, on the calling thread,
+    /// which then blocks forever round-robining each accepted connection out
+    /// to a worker: the connection is converted to a plain `std` socket,
+    /// handed across the thread boundary on a bounded channel, and rebound
+    /// onto the receiving worker's own `Core` there. Each worker owns its own
+    /// `Msg` channel and connection map via an independent `Service`, so
+    /// `conn_id`s are only unique within the worker that assigned them.
+    ///
+    /// A single connection failing to hand off (its `std` conversion erroring,
+    /// or the worker it was routed to having died) is logged and skipped
+    /// rather than tearing down the whole accept loop.
+    pub fn run_multithreaded(addr: &SocketAddr, config: ServiceConfig, workers: usize) -> io::Result<()> {
+        let workers = if workers == 0 { ::num_cpus::get() } else { workers };
+        info!("Starting {} worker reactors", workers);
+
+        let mut worker_txs = Vec::with_capacity(workers);
+        for id in 0..workers {
+            let (tx, rx) = channel(config.channel_capacity);
+            worker_txs.push(tx);
+            thread::spawn(move || run_worker(id, rx, config));
+        }
+
+        info!("Starting TCP listener for {}", addr);
+        let mut core = Core::new()?;
+        let listener = bind_listener(addr, config.reuseaddr, &core.handle())?;
+
+        let mut next_worker = 0;
+        let accept_loop = listener.incoming().for_each(move |(conn, remote_addr)| {
+            let worker = next_worker;
+            next_worker = (next_worker + 1) % worker_txs.len();
+            info!("New connection from {}, handing off to worker {}", remote_addr, worker);
+
+            match into_std_stream(conn) {
+                Ok(std_stream) => {
+                    Box::new(hand_off_connection(&worker_txs[worker], std_stream).then(move |result| {
+                        if let Err(e) = result {
+                            warn!("Failed handing connection from {} off to worker {}: {}", remote_addr, worker, e);
+                        }
+                        Ok(())
+                    })) as Box<Future<Item = (), Error = io::Error>>
+                },
+                Err(e) => {
+                    warn!("Failed to prepare connection from {} for handoff to worker {}: {}", remote_addr, worker, e);
+                    Box::new(::futures::future::ok(())) as Box<Future<Item = (), Error = io::Error>>
+                },
+            }
+        });
+
+        core.run(accept_loop)
+    }
+
     /// Main message handler. Invoked by the main message loop each time the
     /// message queue receives new data.
     fn handle_message(&mut self, msg: Msg) -> Result<(),()> {
@@ -123,28 +290,39 @@ impl Service {
             Msg::ConnectionLost {conn_id} => {
                 info!("Conn {}: Connection lost", conn_id);
                 self.conns.remove(&conn_id);
+                let lost: Vec<SubstreamId> = self.substreams.keys()
+                    .filter(|&&(c, _)| c == conn_id)
+                    .cloned()
+                    .collect();
+                for id in lost {
+                    self.substreams.remove(&id);
+                }
                 Ok(())
             },
 
-            Msg::NewFrame {conn_id, frame} => {
-                info!("Conn {}: New frame: {}", conn_id, frame);
-                if let Some(ref mut conn) = self.conns.get_mut(&conn_id) {
-                    if conn.frame_tx.is_none() {
-                        info!("Conn {}: connection busy, queuing frame.", conn_id);
-
-                        conn.queue.push_back(frame)
-                    } else {
-                        let tx = replace(&mut conn.frame_tx, None).unwrap();
-                        send_frame(conn_id, frame, tx, &self.msg_tx,
-                                   &self.message_loop);
-                    }
-                }
+            Msg::NewMuxFrame {conn_id, frame} => {
+                self.handle_mux_frame(conn_id, frame);
+                Ok(())
+            },
+
+            Msg::StreamOpened {conn_id, stream_id} => {
+                info!("Conn {}: Stream {} opened", conn_id, stream_id);
+                self.substreams.insert((conn_id, stream_id), Substream::new());
+                Ok(())
+            },
+
+            Msg::StreamClosed {conn_id, stream_id} => {
+                info!("Conn {}: Stream {} closed", conn_id, stream_id);
+                self.substreams.remove(&(conn_id, stream_id));
                 Ok(())
             },
 
             Msg::FrameTxComplete {conn_id, new_tx} => {
                 info!("Conn {}: Send Complete.", conn_id);
                 if let Some(ref mut conn) = self.conns.get_mut(&conn_id) {
+                    if queue_can_resume(conn.queue.len(), self.config.queue_low_water) {
+                        conn.read_gate.resume();
+                    }
                     match conn.queue.pop_front() {
                         Some(frame) => {
                             info!("Conn {}: Draining queue. ", conn_id);
@@ -160,13 +338,171 @@ impl Service {
         }
     }
 
+    /// Handles a single demultiplexed frame, updating substream bookkeeping
+    /// as needed and echoing `Data` frames back to the sender.
+    fn handle_mux_frame(&mut self, conn_id: usize, frame: MuxFrame) {
+        let stream_id = frame.stream_id;
+        let sub_id = (conn_id, stream_id);
+
+        match frame.frame_type {
+            FrameType::Open => {
+                let open_count = self.substreams.keys().filter(|&&(c, _)| c == conn_id).count();
+                if open_count >= self.config.max_substreams_per_conn {
+                    warn!("Conn {}: refusing to open stream {}, already at the {} substream cap",
+                          conn_id, stream_id, self.config.max_substreams_per_conn);
+                    self.queue_conn_frame(conn_id, MuxFrame::close(stream_id));
+                    return;
+                }
+                let _ = self.handle_message(Msg::StreamOpened { conn_id: conn_id, stream_id: stream_id });
+            },
+
+            FrameType::Close => {
+                let _ = self.handle_message(Msg::StreamClosed { conn_id: conn_id, stream_id: stream_id });
+            },
+
+            FrameType::WindowUpdate => {
+                let credit = match frame.window_credit() {
+                    Ok(c) => c,
+                    Err(e) => { warn!("Conn {}: {}", conn_id, e); return; }
+                };
+                if let Some(sub) = self.substreams.get_mut(&sub_id) {
+                    sub.send_credit += credit;
+                }
+                self.drain_substream_queue(conn_id, stream_id);
+            },
+
+            FrameType::Data => {
+                let len = frame.payload.len() as u32;
+                let result = {
+                    let sub = match self.substreams.get_mut(&sub_id) {
+                        Some(sub) => sub,
+                        None => {
+                            warn!("Conn {}: Data frame for unopened stream {}", conn_id, stream_id);
+                            return;
+                        }
+                    };
+                    if !debit_recv_window(&mut sub.recv_window, len) {
+                        warn!("Conn {}: Stream {} exceeded its receive window, dropping frame",
+                              conn_id, stream_id);
+                        return;
+                    }
+                    sub.decode_buf.extend_from_slice(&frame.payload);
+                    decode_lines(&mut sub.codec, &mut sub.decode_buf)
+                };
+
+                match result {
+                    Ok(lines) => {
+                        for line in lines {
+                            info!("Conn {}: Stream {}: New frame: {}", conn_id, stream_id, line);
+                            self.queue_substream_send(conn_id, stream_id, line);
+                        }
+
+                        // The echo service consumes the payload immediately, so
+                        // we can return the window credit straight away.
+                        self.grant_window(conn_id, stream_id, len);
+                    },
+                    Err(e) => {
+                        // A substream whose codec errors has no way to get
+                        // back into a known-good framing state, so rather
+                        // than strand it -- still holding its spent receive
+                        // window, forever -- evict it and tell the peer it's
+                        // closed.
+                        warn!("Conn {}: Stream {}: {}, closing stream", conn_id, stream_id, e);
+                        self.substreams.remove(&sub_id);
+                        self.queue_conn_frame(conn_id, MuxFrame::close(stream_id));
+                    },
+                }
+            },
+        }
+    }
+
+    /// Queues `line` for transmission back to the peer on `stream_id`,
+    /// subject to the substream's remaining send credit.
+    fn queue_substream_send(&mut self, conn_id: usize, stream_id: u32, line: String) {
+        let sub_id = (conn_id, stream_id);
+        let ready = {
+            let sub = match self.substreams.get_mut(&sub_id) {
+                Some(sub) => sub,
+                None => return,
+            };
+
+            if sub.send_queue.is_empty() && (line.len() as u32) <= sub.send_credit {
+                sub.send_credit -= line.len() as u32;
+                true
+            } else {
+                sub.send_queue.push_back(line.clone());
+                false
+            }
+        };
+
+        if ready {
+            let payload = encode_line(line);
+            self.queue_conn_frame(conn_id, MuxFrame::data(stream_id, payload));
+        }
+    }
+
+    /// Flushes as much of a substream's pending send queue as its current
+    /// credit allows, in response to a freshly-arrived `WindowUpdate`.
+    fn drain_substream_queue(&mut self, conn_id: usize, stream_id: u32) {
+        let sub_id = (conn_id, stream_id);
+        let ready = {
+            let sub = match self.substreams.get_mut(&sub_id) {
+                Some(sub) => sub,
+                None => return,
+            };
+            drain_ready(&mut sub.send_queue, &mut sub.send_credit)
+        };
+
+        for line in ready {
+            let payload = encode_line(line);
+            self.queue_conn_frame(conn_id, MuxFrame::data(stream_id, payload));
+        }
+    }
+
+    /// Returns `consumed` bytes of window credit to the peer on `stream_id`.
+    fn grant_window(&mut self, conn_id: usize, stream_id: u32, consumed: u32) {
+        if let Some(sub) = self.substreams.get_mut(&(conn_id, stream_id)) {
+            sub.recv_window += consumed;
+        }
+        self.queue_conn_frame(conn_id, MuxFrame::window_update(stream_id, consumed));
+    }
+
+    /// Either sends a frame on the connection's underlying socket straight
+    /// away, or queues it if the socket is already busy sending something
+    /// else. Frames for different substreams share this one queue, since
+    /// they're interleaved over the same TCP connection.
+    fn queue_conn_frame(&mut self, conn_id: usize, frame: MuxFrame) {
+        let high_water = self.config.queue_high_water;
+        if let Some(ref mut conn) = self.conns.get_mut(&conn_id) {
+            if conn.frame_tx.is_none() {
+                conn.queue.push_back(frame);
+                if queue_needs_pause(conn.queue.len(), high_water) {
+                    warn!("Conn {}: outbound queue hit its high-water mark, pausing reads", conn_id);
+                    conn.read_gate.pause();
+                }
+                return;
+            }
+            let tx = replace(&mut conn.frame_tx, None).unwrap();
+            send_frame(conn_id, frame, tx, &self.msg_tx, &self.message_loop);
+        }
+    }
+
     /// Spawns a new connection onto the message loop and adds a corresponding
     /// `Conn` object the the `Service`'s connection list
     fn spawn_connection(&mut self, conn: TcpStream) -> io::Result<()> {
+        if let Err(e) = apply_stream_options(&conn, &self.config) {
+            warn!("Failed to apply socket options to new connection: {}", e);
+        }
+
         // Bind the TCP stream to a framing algorithm that will chop the
-        // incoming bytes into a sequence of well-defined frames, extracting
-        // the tx and rx channels into separate objects.
-        let (frame_tx, frame_rx) = conn.framed(LineCodec::new()).split();
+        // incoming bytes into a sequence of well-defined mux frames,
+        // extracting the tx and rx channels into separate objects.
+        let (frame_tx, frame_rx) = conn.framed(MuxCodec::new()).split();
+
+        // The read half is wrapped in a `Gate` so the connection's outbound
+        // queue (see `queue_conn_frame`) can pause it under backpressure.
+        let read_gate = Gate::new();
+        let frame_rx = read_gate.throttle(frame_rx);
 
         // Make copies of the channel back to the main message handler,
         // otherwise we'll have all sorts of lifetime issues because we can't
@@ -180,16 +516,22 @@ impl Service {
         self.conn_count += 1;
 
         // Define a future that will iterate over the incoming frames, and then
-        // signal the main loop when the connection is dropped.
+        // signal the main loop when the connection is dropped -- `.then`
+        // rather than `.and_then` so a decode error (e.g. `MuxCodec` hitting
+        // an unknown frame type) still reports `ConnectionLost` instead of
+        // leaking the `Conn` and its substreams forever.
         let frame_handler = frame_rx
             .for_each(move |frame| {
-                let msg = Msg::NewFrame {
+                let msg = Msg::NewMuxFrame {
                     conn_id: conn_id,
                     frame: frame
                 };
                 send_msg(&tx_loop, msg)
             })
-            .and_then(move |_| {
+            .then(move |result| {
+                if let Err(ref e) = result {
+                    warn!("Conn {}: frame stream ended with an error: {}", conn_id, e);
+                }
                 send_msg(&tx_conn_lost, Msg::ConnectionLost {conn_id: conn_id})
             })
             .map_err(erase);
@@ -199,27 +541,165 @@ impl Service {
         self.message_loop.spawn(frame_handler);
 
         // record the new connection in the service connection list
-        let conn = Conn::new(frame_tx);
+        info!("Conn {}: options = {:?}", conn_id, self.config);
+        let conn = Conn::new(frame_tx, read_gate);
         self.conns.insert(conn_id, conn);
         Ok(())
     }
 }
 
-/// Sends a `Msg` on the supplied message channel, mapping the result to be
-/// compatible with the futures library
-fn send_msg(tx: &UnboundedSender<Msg>, msg: Msg) -> io::Result<()> {
-    tx.send(msg).map_err(|e| io::Error::new(ErrorKind::Other, e))
+/// Drives one worker reactor spawned by `Service::run_multithreaded`: owns
+/// its own `Core` and its own `Service`, and adopts each connection that
+/// arrives on `incoming` by rebinding it onto that `Core` and feeding it into
+/// `Service::spawn_connection`, exactly as `start_listener`'s accept loop
+/// would for a directly-accepted connection.
+fn run_worker(id: usize, incoming: Receiver<StdTcpStream>, config: ServiceConfig) {
+    let mut core = match Core::new() {
+        Ok(core) => core,
+        Err(e) => {
+            error!("Worker {}: failed to create reactor: {}", id, e);
+            return;
+        }
+    };
+    let handle = core.handle();
+
+    // `service` is shared between the two futures below -- adopting incoming
+    // connections and running the message loop -- which both need to mutate
+    // it while running concurrently on this one thread's `Core`.
+    let service = Rc::new(RefCell::new(Service::with_config(&handle, config)));
+
+    let adopt_service = service.clone();
+    let adopt_connections = incoming
+        .for_each(move |std_stream| {
+            match TcpStream::from_stream(std_stream, &handle) {
+                Ok(stream) => {
+                    if let Err(e) = adopt_service.borrow_mut().spawn_connection(stream) {
+                        warn!("Worker {}: failed to spawn connection: {}", id, e);
+                    }
+                },
+                Err(e) => warn!("Worker {}: failed to adopt a connection onto its reactor: {}", id, e),
+            }
+            Ok(())
+        })
+        .map_err(erase);
+
+    let event_rx = replace(&mut service.borrow_mut().msg_rx, None).unwrap();
+    let event_handler = event_rx
+        .for_each(move |msg| service.borrow_mut().handle_message(msg))
+        .map_err(erase);
+
+    info!("Worker {}: running", id);
+    if let Err(_) = core.run(adopt_connections.join(event_handler)) {
+        error!("Worker {}: event loop returned error!", id);
+    }
+}
+
+/// Converts an accepted `tokio_core` stream into a plain `std` socket so it
+/// can be handed across a thread boundary and rebound onto a different
+/// worker's reactor with `TcpStream::from_stream`.
+///
+/// This clones the fd via a borrowed `std` view rather than `forget`-ing
+/// `stream` itself the way `set_linger` forgets its borrowed handle: `
+/// TcpStream`'s `Drop` impl both closes its fd *and* deregisters it from
+/// this thread's reactor, and skipping that would leak the registration on
+/// the accept thread for every connection handed off, which is exactly the
+/// thread this feature needs to stay lightweight under connection churn.
+/// Letting `stream` drop normally at the end of this function gets us that
+/// deregistration for free; the clone keeps its own independent fd for the
+/// worker to adopt.
+#[cfg(unix)]
+fn into_std_stream(stream: TcpStream) -> io::Result<StdTcpStream> {
+    use std::mem::forget;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    let borrowed = unsafe { StdTcpStream::from_raw_fd(stream.as_raw_fd()) };
+    let cloned = borrowed.try_clone();
+    forget(borrowed);
+    cloned
+}
+
+#[cfg(not(unix))]
+fn into_std_stream(_stream: TcpStream) -> io::Result<StdTcpStream> {
+    Err(io::Error::new(ErrorKind::Other, "multi-threaded mode is only supported on unix"))
+}
+
+/// Hands a freshly-accepted connection off to a worker, in the same
+/// send-and-report-back style as `send_msg`: the returned future doesn't
+/// complete until the worker's channel has room, which is how backpressure
+/// on a slow worker propagates back to the accept loop.
+fn hand_off_connection(tx: &Sender<StdTcpStream>, std_stream: StdTcpStream) -> Box<Future<Item = (), Error = io::Error>> {
+    Box::new(tx.clone()
+        .send(std_stream)
+        .map(|_| ())
+        .map_err(|e| io::Error::new(ErrorKind::Other, e)))
+}
+
+/// Binds the listening socket, applying `SO_REUSEADDR` first if requested
+/// since it must be set before the socket is bound.
+fn bind_listener(addr: &SocketAddr, reuseaddr: bool, handle: &Handle) -> io::Result<TcpListener> {
+    if !reuseaddr {
+        return TcpListener::bind(addr, handle);
+    }
+
+    let builder = match *addr {
+        SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+        SocketAddr::V6(_) => TcpBuilder::new_v6()?,
+    };
+    builder.reuse_address(true)?;
+    builder.bind(addr)?;
+    let listener = builder.listen(1024)?;
+    TcpListener::from_listener(listener, addr, handle)
+}
+
+/// Applies `config`'s per-connection socket options to a freshly-accepted
+/// stream.
+fn apply_stream_options(stream: &TcpStream, config: &ServiceConfig) -> io::Result<()> {
+    stream.set_nodelay(config.nodelay)?;
+    stream.set_keepalive(config.keepalive)?;
+    set_linger(stream, config.linger)?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_linger(stream: &TcpStream, linger: Option<Duration>) -> io::Result<()> {
+    use std::mem::forget;
+    use std::net;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use net2::TcpStreamExt;
+
+    // `tokio_core::net::TcpStream` doesn't expose SO_LINGER directly, so we
+    // borrow the raw fd into a std socket just long enough to set it via
+    // `net2`, then forget the borrowed value so it doesn't close our fd.
+    let borrowed = unsafe { net::TcpStream::from_raw_fd(stream.as_raw_fd()) };
+    let result = TcpStreamExt::set_linger(&borrowed, linger);
+    forget(borrowed);
+    result
+}
+
+#[cfg(not(unix))]
+fn set_linger(_stream: &TcpStream, _linger: Option<Duration>) -> io::Result<()> {
+    Ok(())
+}
+
+/// Sends a `Msg` on the supplied bounded message channel. The returned
+/// future doesn't complete until there's room in the channel, which is how
+/// backpressure on the `Msg` channel propagates out to whoever is trying to
+/// post to it (the accept loop, a connection's frame handler, and so on).
+fn send_msg(tx: &Sender<Msg>, msg: Msg) -> Box<Future<Item = (), Error = io::Error>> {
+    Box::new(tx.clone()
+        .send(msg)
+        .map(|_| ())
+        .map_err(|e| io::Error::new(ErrorKind::Other, e)))
 }
 
 /// Sends a frame on the supplied `FrameSender`, consuming the sender and
 /// sending a `FrameTxComplete` message to the main message loop when it's
 /// done.
 fn send_frame(conn_id: usize,
-              frame: String,
+              frame: MuxFrame,
               tx: FrameSender,
-              channel_ref: &UnboundedSender<Msg>,
+              channel_ref: &Sender<Msg>,
               message_loop: &Handle) {
-    use futures::Sink;
     let channel = channel_ref.clone();
 
     let send_frame =
@@ -238,4 +718,123 @@ fn send_frame(conn_id: usize,
 
 fn erase<T>(_: T) -> () {
     ()
-}
\ No newline at end of file
+}
+
+/// Debits `len` bytes from a substream's remaining receive window, returning
+/// `false` (and leaving the window untouched) if that would exceed what the
+/// peer has been granted -- the caller should drop the frame in that case.
+fn debit_recv_window(recv_window: &mut u32, len: u32) -> bool {
+    if len > *recv_window {
+        return false;
+    }
+    *recv_window -= len;
+    true
+}
+
+/// Pops as many lines off the front of `send_queue` as `send_credit` covers,
+/// debiting credit for each and returning them in order. Stops at the first
+/// line that doesn't fit, even if a shorter one further back would: flow
+/// control preserves FIFO ordering on a substream rather than reordering
+/// around a stalled head-of-line item.
+fn drain_ready(send_queue: &mut VecDeque<String>, send_credit: &mut u32) -> Vec<String> {
+    let mut ready = Vec::new();
+    loop {
+        match send_queue.front() {
+            Some(line) if (line.len() as u32) <= *send_credit => {
+                *send_credit -= line.len() as u32;
+            },
+            _ => break,
+        }
+        ready.push(send_queue.pop_front().unwrap());
+    }
+    ready
+}
+
+/// Whether a connection's outbound queue has grown enough that reads from it
+/// should be paused.
+fn queue_needs_pause(queue_len: usize, high_water: usize) -> bool {
+    queue_len >= high_water
+}
+
+/// Whether a paused connection's outbound queue has drained enough that
+/// reads from it should resume.
+fn queue_can_resume(queue_len: usize, low_water: usize) -> bool {
+    queue_len <= low_water
+}
+
+/// Drains as many complete lines as `codec` can find out of `buf`, leaving
+/// any trailing partial line buffered for the next `Data` frame.
+fn decode_lines(codec: &mut LineCodec, buf: &mut BytesMut) -> io::Result<Vec<String>> {
+    let mut lines = Vec::new();
+    while let Some(line) = codec.decode(buf)? {
+        lines.push(line);
+    }
+    Ok(lines)
+}
+
+/// Encodes a single line with a fresh `LineCodec`, for handing to
+/// `MuxFrame::data` as a substream payload.
+fn encode_line(line: String) -> Bytes {
+    let mut buf = BytesMut::new();
+    LineCodec::new().encode(line, &mut buf).expect("LineCodec::encode is infallible");
+    buf.freeze()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{debit_recv_window, drain_ready, queue_can_resume, queue_needs_pause};
+    use std::collections::VecDeque;
+
+    #[test]
+    fn debit_recv_window_consumes_credit_within_the_window() {
+        let mut recv_window = 100u32;
+        assert!(debit_recv_window(&mut recv_window, 40));
+        assert_eq!(60, recv_window);
+    }
+
+    #[test]
+    fn debit_recv_window_rejects_a_frame_that_exceeds_the_window() {
+        let mut recv_window = 100u32;
+        assert!(!debit_recv_window(&mut recv_window, 101));
+        assert_eq!(100, recv_window, "a rejected frame must not touch the window");
+    }
+
+    #[test]
+    fn drain_ready_drains_everything_that_fits_in_order() {
+        let mut queue: VecDeque<String> = vec!["a".to_string(), "bb".to_string(), "ccc".to_string()].into();
+        let mut credit = 100u32;
+
+        let drained = drain_ready(&mut queue, &mut credit);
+        assert_eq!(vec!["a", "bb", "ccc"], drained);
+        assert_eq!(94, credit);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn drain_ready_stops_at_the_first_line_that_does_not_fit() {
+        let mut queue: VecDeque<String> = vec!["a".to_string(), "ccc".to_string(), "b".to_string()].into();
+        let mut credit = 2u32;
+
+        let drained = drain_ready(&mut queue, &mut credit);
+        assert_eq!(vec!["a"], drained);
+        assert_eq!(1, credit);
+
+        // The too-big line stays at the front of the queue rather than
+        // letting the shorter line behind it jump ahead.
+        assert_eq!(vec!["ccc".to_string(), "b".to_string()], queue.into_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn queue_needs_pause_trips_at_the_high_water_mark() {
+        assert!(!queue_needs_pause(255, 256));
+        assert!(queue_needs_pause(256, 256));
+        assert!(queue_needs_pause(257, 256));
+    }
+
+    #[test]
+    fn queue_can_resume_once_drained_to_the_low_water_mark() {
+        assert!(!queue_can_resume(65, 64));
+        assert!(queue_can_resume(64, 64));
+        assert!(queue_can_resume(0, 64));
+    }
+}
@@ -1,78 +1,450 @@
-use futures::{Future, Stream};
-use futures::unsync::mpsc::unbounded;
-use tokio_core::reactor::Handle;
-use tokio_core::net::TcpListener;
+use bytes::BytesMut;
+use futures::{Future, Stream, Sink};
+use futures::unsync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded};
+use futures::stream::SplitSink;
+use tokio_core::reactor::{Core, Handle};
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_io::AsyncRead;
+use tokio_io::codec::{Encoder, Decoder, Framed, FramedParts};
+
+use std::collections::{BTreeMap, VecDeque};
 use std::fmt;
+use std::io::{self, ErrorKind};
+use std::mem::replace;
 use std::net::SocketAddr;
-use std::thread;
+use std::str;
+
+use sha1::Sha1;
+use base64;
+
+use backpressure::Gate;
+use ws_codec::{WsCodec, WsMessage};
+
+/// Once a connection's outbound frame queue reaches this many queued
+/// frames, the service stops reading further frames from that connection
+/// until the queue drains back to `QUEUE_LOW_WATER`. Mirrors
+/// `ServiceConfig::queue_high_water`/`queue_low_water` for plain TCP
+/// connections; `WebsocketServer` has no equivalent config struct yet, so
+/// these are plain constants rather than per-server tuning knobs.
+const QUEUE_HIGH_WATER: usize = 256;
+const QUEUE_LOW_WATER: usize = 64;
+
+/// The magic GUID that RFC6455 section 1.3 has the server append to the
+/// client's `Sec-WebSocket-Key` before hashing, to prove that the response
+/// was generated by a server that actually understood the handshake.
+const WEBSOCKET_GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
 
+#[derive(Debug)]
 pub enum WsError {
     AddrInUse,
+    HandshakeFailed(String),
 }
 
 impl fmt::Display for WsError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let msg = match self {
-            AddrInUse => "Address In Use",
-        };
-        f.write_str(msg)
+        match *self {
+            WsError::AddrInUse => f.write_str("Address In Use"),
+            WsError::HandshakeFailed(ref reason) => write!(f, "Handshake failed: {}", reason),
+        }
     }
 }
 
 pub type WsResult<T> = Result<T, WsError>;
 
+/// A shorthand definition of the Sink type for sending WebSocket frames to a
+/// remote client.
+type FrameSender = SplitSink<Framed<TcpStream, WsCodec>>;
+
+/// The range of messages our message handling function can deal with.
+enum Msg {
+    NewConnection { conn: TcpStream, remote_addr: SocketAddr },
+    HandshakeComplete { conn_id: usize, frame_tx: FrameSender },
+    HandshakeFailed { conn_id: usize },
+    ConnectionLost { conn_id: usize },
+    NewFrame { conn_id: usize, frame: WsMessage },
+    FrameTxComplete { conn_id: usize, new_tx: FrameSender },
+}
+
+/// Represents an individual WebSocket connection. A `Conn` is "busy" (in the
+/// process of handshaking, or already sending data) whenever `frame_tx` is
+/// `None`; any frames destined for the client are queued until it becomes
+/// `Some` again, mirroring the bookkeeping `service::Conn` does for plain
+/// TCP connections.
+///
+/// `queue` is capped at `QUEUE_HIGH_WATER`: once it's full, `read_gate` is
+/// paused so the connection's frame stream stops yielding new frames until
+/// the queue drains back to `QUEUE_LOW_WATER`, giving the same per-connection
+/// backpressure `service::Conn` has instead of unbounded buffering.
+struct Conn {
+    queue: VecDeque<WsMessage>,
+    frame_tx: Option<FrameSender>,
+    read_gate: Gate,
+}
+
+impl Conn {
+    fn pending(read_gate: Gate) -> Conn {
+        Conn { queue: VecDeque::new(), frame_tx: None, read_gate: read_gate }
+    }
+}
+
+/// Implements a WebSocket echo service: accepts raw TCP connections,
+/// performs the RFC6455 opening handshake on each, and then echoes back
+/// every text/binary message it receives, answering Ping with Pong and
+/// Close with Close.
 pub struct WebsocketServer {
+    conns:        BTreeMap<usize, Conn>,
+    msg_tx:       UnboundedSender<Msg>,
+    msg_rx:       Option<UnboundedReceiver<Msg>>,
+    message_loop: Handle,
+    conn_count:   usize,
 }
 
 impl WebsocketServer {
+    /// Constructs a new `WebsocketServer` and starts it listening on `addr`.
     pub fn new(addr: SocketAddr, h: &Handle) -> WsResult<WebsocketServer> {
-        use core::cell::RefCell;
-
-        let listener = try!(TcpListener::bind(&addr, h)
-                            .map_err(|_| WsError::AddrInUse));
-
-        // create a channel that this listener can report new connections on
-        let (mut connection_tx, connection_rx) = unbounded();
-
-        // Set up a task that will run every time a new connection is received
-        let pickup = connection_rx.for_each(|(s, addr)| {
-            println!("{:?} New connection from {}", thread::current(), addr);
-            Ok(())
-        });
-        h.spawn(pickup);
-
-        // Convert the TCP listener into a future that will produce a stream of
-        // incoming connections, each of which will then be routed back along the
-        // channel we just created
-        let handler = listener.incoming().for_each(move |c| {
-            println!("{:?} Connection accepted", thread::current());
-            if let Err(e) = connection_tx.send(c) {
-                println!("{:?} sending inbound connection failed: {}", thread::current(), e)
-            };
-            Ok(())
-        });
-
-        h.spawn(handler.map_err(|_| ()));
-
-        Ok(WebsocketServer{})
+        info!("Creating messaging channels");
+        let (tx, rx) = unbounded();
+
+        let mut server = WebsocketServer {
+            conns: BTreeMap::new(),
+            msg_tx: tx,
+            msg_rx: Some(rx),
+            message_loop: h.clone(),
+            conn_count: 0,
+        };
+
+        server.start_listener(&addr)?;
+        Ok(server)
+    }
+
+    /// Starts a listener on the supplied socket address.
+    fn start_listener(&self, addr: &SocketAddr) -> WsResult<()> {
+        info!("Starting WebSocket listener for {}", addr);
+
+        let listener = TcpListener::bind(addr, &self.message_loop)
+            .map_err(|_| WsError::AddrInUse)?;
+
+        let tx = self.msg_tx.clone();
+        let accept_handler = listener.incoming()
+            .for_each(move |(conn, remote_addr)| {
+                let msg = Msg::NewConnection { conn: conn, remote_addr: remote_addr };
+                send_msg(&tx, msg)
+            })
+            .map_err(erase);
+
+        self.message_loop.spawn(accept_handler);
+        Ok(())
+    }
+
+    /// Runs the service, blocking the calling thread until it returns.
+    pub fn run(&mut self, msg_loop: &mut Core) {
+        let rx = replace(&mut self.msg_rx, None).unwrap();
+        let event_handler = rx.for_each(|msg| self.handle_message(msg));
+        if let Err(_) = msg_loop.run(event_handler) {
+            error!("Event loop returned error!")
+        }
+    }
+
+    /// Main message handler. Invoked by the main message loop each time the
+    /// message queue receives new data.
+    fn handle_message(&mut self, msg: Msg) -> Result<(), ()> {
+        match msg {
+            Msg::NewConnection { conn, remote_addr } => {
+                info!("New websocket connection from {}", remote_addr);
+                self.spawn_handshake(conn);
+                Ok(())
+            }
+
+            Msg::HandshakeComplete { conn_id, frame_tx } => {
+                info!("Conn {}: Handshake complete", conn_id);
+                if let Some(conn) = self.conns.get_mut(&conn_id) {
+                    conn.frame_tx = Some(frame_tx);
+                }
+                Ok(())
+            }
+
+            Msg::HandshakeFailed { conn_id } => {
+                info!("Conn {}: Handshake failed", conn_id);
+                self.conns.remove(&conn_id);
+                Ok(())
+            }
+
+            Msg::ConnectionLost { conn_id } => {
+                info!("Conn {}: Connection lost", conn_id);
+                self.conns.remove(&conn_id);
+                Ok(())
+            }
+
+            Msg::NewFrame { conn_id, frame } => {
+                let reply = match frame {
+                    WsMessage::Text(ref s) => {
+                        info!("Conn {}: New frame: {}", conn_id, s);
+                        Some(WsMessage::Text(s.clone()))
+                    }
+                    WsMessage::Binary(ref b) => Some(WsMessage::Binary(b.clone())),
+                    WsMessage::Ping(ref payload) => Some(WsMessage::Pong(payload.clone())),
+                    WsMessage::Pong(_) => None,
+                    WsMessage::Close => Some(WsMessage::Close),
+                };
+
+                if let Some(frame) = reply {
+                    self.queue_frame(conn_id, frame);
+                }
+                Ok(())
+            }
+
+            Msg::FrameTxComplete { conn_id, new_tx } => {
+                info!("Conn {}: Send Complete.", conn_id);
+                if let Some(conn) = self.conns.get_mut(&conn_id) {
+                    if conn.queue.len() <= QUEUE_LOW_WATER {
+                        conn.read_gate.resume();
+                    }
+                    match conn.queue.pop_front() {
+                        Some(frame) => send_frame(conn_id, frame, new_tx, &self.msg_tx, &self.message_loop),
+                        None => conn.frame_tx = Some(new_tx),
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Either sends `frame` immediately, if the connection's sink is free,
+    /// or queues it for later delivery.
+    fn queue_frame(&mut self, conn_id: usize, frame: WsMessage) {
+        if let Some(conn) = self.conns.get_mut(&conn_id) {
+            if conn.frame_tx.is_none() {
+                conn.queue.push_back(frame);
+                if conn.queue.len() >= QUEUE_HIGH_WATER {
+                    warn!("Conn {}: outbound queue hit its high-water mark, pausing reads", conn_id);
+                    conn.read_gate.pause();
+                }
+                return;
+            }
+            let tx = replace(&mut conn.frame_tx, None).unwrap();
+            send_frame(conn_id, frame, tx, &self.msg_tx, &self.message_loop);
+        }
+    }
+
+    /// Registers a new connection, then drives it through the opening
+    /// handshake before it's allowed to exchange WebSocket frames.
+    fn spawn_handshake(&mut self, conn: TcpStream) {
+        let conn_id = self.conn_count;
+        self.conn_count += 1;
+        let read_gate = Gate::new();
+        self.conns.insert(conn_id, Conn::pending(read_gate.clone()));
+
+        let tx_complete = self.msg_tx.clone();
+        let tx_frames = self.msg_tx.clone();
+        let tx_lost = self.msg_tx.clone();
+        let tx_failed = self.msg_tx.clone();
+        let message_loop = self.message_loop.clone();
+        let message_loop2 = self.message_loop.clone();
+
+        let handshake = conn.framed(HandshakeCodec::new())
+            .into_future()
+            .map_err(|(e, _)| e)
+            .and_then(|(request, framed)| {
+                let request = request.ok_or_else(|| {
+                    io::Error::new(ErrorKind::UnexpectedEof, "connection closed during handshake")
+                })?;
+                let response = build_handshake_response(&request)
+                    .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+                Ok((response, framed))
+            })
+            .and_then(|(response, framed)| framed.send(response))
+            .and_then(move |framed| {
+                // `into_inner` would silently drop any bytes `HandshakeCodec`
+                // had already read past `\r\n\r\n` -- plausible if a
+                // non-browser client writes its first WebSocket frame
+                // without waiting for the `101` response. Carry them over
+                // into the new `Framed`'s read buffer via `into_parts`
+                // instead of discarding them.
+                let parts = framed.into_parts();
+                let mut ws_parts = FramedParts::new(parts.io, WsCodec::new());
+                ws_parts.read_buf = parts.read_buf;
+                let (frame_tx, frame_rx) = Framed::from_parts(ws_parts).split();
+
+                // `.then` rather than `.and_then`: a decode error (e.g.
+                // `WsCodec` hitting a bad opcode or invalid UTF-8) must still
+                // report `ConnectionLost`, or the `Conn` it leaves behind is
+                // never removed from `self.conns`.
+                let frame_handler = read_gate.throttle(frame_rx)
+                    .for_each(move |frame| {
+                        send_msg(&tx_frames, Msg::NewFrame { conn_id: conn_id, frame: frame })
+                    })
+                    .then(move |result| {
+                        if let Err(ref e) = result {
+                            warn!("Conn {}: frame stream ended with an error: {}", conn_id, e);
+                        }
+                        send_msg(&tx_lost, Msg::ConnectionLost { conn_id: conn_id })
+                    })
+                    .map_err(erase);
+                message_loop.spawn(frame_handler);
+
+                send_msg(&tx_complete, Msg::HandshakeComplete { conn_id: conn_id, frame_tx: frame_tx })
+            });
+
+        message_loop2.spawn(handshake.map_err(move |e| {
+            error!("Conn {}: {}", conn_id, e);
+            let _ = tx_failed.send(Msg::HandshakeFailed { conn_id: conn_id });
+        }));
+    }
+}
+
+/// Sends a `Msg` on the supplied message channel, mapping the result to be
+/// compatible with the futures library
+fn send_msg(tx: &UnboundedSender<Msg>, msg: Msg) -> io::Result<()> {
+    tx.send(msg).map_err(|e| io::Error::new(ErrorKind::Other, e))
+}
+
+/// Sends a frame on the supplied `FrameSender`, consuming the sender and
+/// sending a `FrameTxComplete` message to the main message loop when it's
+/// done.
+fn send_frame(conn_id: usize,
+              frame: WsMessage,
+              tx: FrameSender,
+              channel_ref: &UnboundedSender<Msg>,
+              message_loop: &Handle) {
+    let channel = channel_ref.clone();
+
+    let send_frame = tx.send(frame)
+        .and_then(move |new_tx| {
+            send_msg(&channel, Msg::FrameTxComplete { conn_id: conn_id, new_tx: new_tx })
+        })
+        .map_err(erase);
+
+    message_loop.spawn(send_frame)
+}
+
+fn erase<T>(_: T) -> () { () }
+
+/// The default cap on how large an in-progress handshake's headers may grow
+/// before `HandshakeCodec` gives up on it. This runs directly on a raw,
+/// unauthenticated socket before any handshake has completed, so without a
+/// limit a client that never sends `\r\n\r\n` could force unbounded
+/// buffering.
+const DEFAULT_MAX_HEADER_LEN: usize = 8 * 1024;
+
+/// A minimal codec that reads raw bytes up to the blank line terminating an
+/// HTTP request's headers, and writes raw bytes back out unmodified. Sibling
+/// in spirit to `lines::LineCodec`, but frames on `\r\n\r\n` instead of `\n`.
+///
+/// Like `LineCodec`, `decode` is incremental: `next_search_index` remembers
+/// how far it's already scanned, so headers trickling in across many small
+/// reads aren't rescanned from the start every time. Because the needle is
+/// four bytes rather than one, the cursor only advances to within
+/// `needle.len() - 1` of the end of the buffer, so a match straddling the
+/// boundary between two reads still can't be missed.
+struct HandshakeCodec {
+    max_header_len: usize,
+    next_search_index: usize,
+}
+
+impl HandshakeCodec {
+    fn new() -> HandshakeCodec {
+        HandshakeCodec {
+            max_header_len: DEFAULT_MAX_HEADER_LEN,
+            next_search_index: 0,
+        }
+    }
+}
+
+impl Decoder for HandshakeCodec {
+    type Item = String;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<String>> {
+        const NEEDLE: &'static [u8] = b"\r\n\r\n";
+        let search_from = self.next_search_index;
+
+        match find_subslice(&buf[search_from..], NEEDLE) {
+            Some(offset) => {
+                let n = search_from + offset;
+                let head = buf.split_to(n);
+                buf.split_to(NEEDLE.len());
+                self.next_search_index = 0;
+
+                match str::from_utf8(&head) {
+                    Ok(s) => Ok(Some(s.to_string())),
+                    Err(e) => Err(io::Error::new(ErrorKind::InvalidData, e)),
+                }
+            },
+            None => {
+                self.next_search_index = buf.len().saturating_sub(NEEDLE.len() - 1);
+                if buf.len() > self.max_header_len {
+                    Err(io::Error::new(ErrorKind::InvalidData,
+                                        format!("handshake headers of at least {} bytes exceed the {} byte limit",
+                                                buf.len(), self.max_header_len)))
+                } else {
+                    Ok(None)
+                }
+            },
+        }
+    }
+}
+
+impl Encoder for HandshakeCodec {
+    type Item = String;
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: String, buf: &mut BytesMut) -> io::Result<()> {
+        buf.extend(msg.as_bytes());
+        Ok(())
     }
 }
 
+fn find_subslice(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Validates the request headers and builds the `101 Switching Protocols`
+/// response, per RFC6455 section 4.2.
+fn build_handshake_response(request: &str) -> Result<String, String> {
+    let mut lines = request.split("\r\n");
+    let request_line = lines.next().ok_or_else(|| "empty request".to_string())?;
+    if !request_line.starts_with("GET ") {
+        return Err(format!("expected a GET request, got: {}", request_line));
+    }
 
-//struct HandleConnection {
-//    tx: Sender<(TcpStream, SocketAddr)>
-//}
-//
-//impl FnMut<(TcpStream, SocketAddr)> for HandleConnection {
-//    fn call_mut(&mut self, conn: (TcpStream, SocketAddr)) -> Result<(), io::Error> {
-//        if let Ok(tx) = self.tx.send(conn).wait() {
-//            self.tx = tx;
-//        }
-//        Ok(())
-//    }
-//}
-
-//fn handle_connection(s: TcpStream) -> io::Result<()> {
-//    let transport = s.framed(TwistCodec::default());
-//    transport.for_each
-//}
\ No newline at end of file
+    let mut upgrade = false;
+    let mut key = None;
+
+    for line in lines {
+        if line.is_empty() {
+            continue;
+        }
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim();
+        let value = parts.next().unwrap_or("").trim();
+
+        if name.eq_ignore_ascii_case("upgrade") && value.eq_ignore_ascii_case("websocket") {
+            upgrade = true;
+        } else if name.eq_ignore_ascii_case("sec-websocket-key") {
+            key = Some(value.to_string());
+        }
+    }
+
+    if !upgrade {
+        return Err("missing 'Upgrade: websocket' header".to_string());
+    }
+    let key = key.ok_or_else(|| "missing Sec-WebSocket-Key header".to_string())?;
+
+    let accept = accept_key(&key);
+    Ok(format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept
+    ))
+}
+
+/// Computes `base64(sha1(key + GUID))`, the value the client uses to verify
+/// that the server actually understood its handshake request.
+fn accept_key(client_key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(client_key.as_bytes());
+    hasher.update(WEBSOCKET_GUID.as_bytes());
+    base64::encode(&hasher.digest().bytes())
+}
@@ -0,0 +1,357 @@
+use bytes::{BufMut, BytesMut};
+use tokio_io::codec::{Encoder, Decoder};
+use std::io;
+use std::str;
+
+/// The WebSocket opcodes we understand, as defined in RFC6455 section 5.2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OpCode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl OpCode {
+    fn from_byte(b: u8) -> io::Result<OpCode> {
+        match b {
+            0x0 => Ok(OpCode::Continuation),
+            0x1 => Ok(OpCode::Text),
+            0x2 => Ok(OpCode::Binary),
+            0x8 => Ok(OpCode::Close),
+            0x9 => Ok(OpCode::Ping),
+            0xA => Ok(OpCode::Pong),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                     format!("unsupported websocket opcode: {:#x}", b))),
+        }
+    }
+
+    fn to_byte(&self) -> u8 {
+        match *self {
+            OpCode::Continuation => 0x0,
+            OpCode::Text => 0x1,
+            OpCode::Binary => 0x2,
+            OpCode::Close => 0x8,
+            OpCode::Ping => 0x9,
+            OpCode::Pong => 0xA,
+        }
+    }
+}
+
+/// A single, reassembled WebSocket message. Fragmented text/binary frames
+/// are joined by the decoder before being handed to the caller as one of
+/// these.
+#[derive(Debug, Clone, PartialEq)]
+pub enum WsMessage {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close,
+}
+
+/// The header fields of a single WebSocket frame, decoded but with the
+/// payload not yet read out of `buf`.
+struct FrameHeader {
+    fin: bool,
+    opcode: OpCode,
+    mask: Option<[u8; 4]>,
+    payload_len: usize,
+    header_len: usize,
+}
+
+/// Reads a frame header from the front of `buf`, returning `None` if `buf`
+/// does not yet hold enough bytes to know the header's length (i.e. we
+/// haven't seen the extended length or masking key yet).
+fn parse_header(buf: &[u8]) -> io::Result<Option<FrameHeader>> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+
+    let fin = buf[0] & 0x80 != 0;
+    let opcode = OpCode::from_byte(buf[0] & 0x0F)?;
+    let masked = buf[1] & 0x80 != 0;
+    let len_field = buf[1] & 0x7F;
+
+    let mut pos = 2;
+    let payload_len = if len_field == 126 {
+        if buf.len() < pos + 2 { return Ok(None); }
+        let len = ((buf[pos] as usize) << 8) | (buf[pos + 1] as usize);
+        pos += 2;
+        len
+    } else if len_field == 127 {
+        if buf.len() < pos + 8 { return Ok(None); }
+        let mut len: u64 = 0;
+        for i in 0..8 {
+            len = (len << 8) | buf[pos + i] as u64;
+        }
+        pos += 8;
+        len as usize
+    } else {
+        len_field as usize
+    };
+
+    let mask = if masked {
+        if buf.len() < pos + 4 { return Ok(None); }
+        let key = [buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]];
+        pos += 4;
+        Some(key)
+    } else {
+        None
+    };
+
+    Ok(Some(FrameHeader {
+        fin: fin,
+        opcode: opcode,
+        mask: mask,
+        payload_len: payload_len,
+        header_len: pos,
+    }))
+}
+
+fn unmask(payload: &mut [u8], key: [u8; 4]) {
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b ^= key[i % 4];
+    }
+}
+
+/// The default cap on a reassembled Text/Binary message, in bytes. Without
+/// one, a peer could dribble an unbounded number of fragments in and force
+/// `fragment_buf` to grow without limit.
+const DEFAULT_MAX_MESSAGE_LEN: usize = 16 * 1024 * 1024;
+
+/// Implements the WebSocket framing protocol (RFC6455 section 5). Incoming
+/// frames are unmasked and, for Text/Binary, reassembled across
+/// fragmentation before being surfaced as a `WsMessage`. Outgoing frames are
+/// written unmasked, as is expected of a server.
+///
+/// `max_message_len` bounds the reassembled size of a single Text/Binary
+/// message (summed across all of its fragments); a message that would grow
+/// past it is rejected rather than letting `fragment_buf` grow without
+/// bound.
+pub struct WsCodec {
+    fragment_opcode: Option<OpCode>,
+    fragment_buf: Vec<u8>,
+    max_message_len: usize,
+}
+
+impl WsCodec {
+    pub fn new() -> WsCodec {
+        WsCodec {
+            fragment_opcode: None,
+            fragment_buf: Vec::new(),
+            max_message_len: DEFAULT_MAX_MESSAGE_LEN,
+        }
+    }
+
+    /// Constructs a `WsCodec` that rejects any reassembled message larger
+    /// than `max_message_len`, instead of the default.
+    pub fn with_max_message_len(max_message_len: usize) -> WsCodec {
+        WsCodec {
+            fragment_opcode: None,
+            fragment_buf: Vec::new(),
+            max_message_len: max_message_len,
+        }
+    }
+}
+
+impl Decoder for WsCodec {
+    type Item = WsMessage;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<WsMessage>> {
+        loop {
+            let header = match parse_header(buf)? {
+                Some(h) => h,
+                None => return Ok(None),
+            };
+
+            let frame_len = header.header_len + header.payload_len;
+            if buf.len() < frame_len {
+                return Ok(None);
+            }
+
+            let frame = buf.split_to(frame_len);
+            let mut payload = frame[header.header_len..].to_vec();
+            if let Some(key) = header.mask {
+                unmask(&mut payload, key);
+            }
+
+            match header.opcode {
+                OpCode::Ping => return Ok(Some(WsMessage::Ping(payload))),
+                OpCode::Pong => return Ok(Some(WsMessage::Pong(payload))),
+                OpCode::Close => return Ok(Some(WsMessage::Close)),
+
+                OpCode::Continuation => {
+                    if self.fragment_opcode.is_none() {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                   "continuation frame with no preceding fragment"));
+                    }
+                    if self.fragment_buf.len() + payload.len() > self.max_message_len {
+                        self.fragment_opcode = None;
+                        self.fragment_buf.clear();
+                        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                   format!("reassembled message exceeds the {} byte limit",
+                                                           self.max_message_len)));
+                    }
+                    self.fragment_buf.extend_from_slice(&payload);
+                    if header.fin {
+                        let opcode = self.fragment_opcode.take().unwrap();
+                        let data = ::std::mem::replace(&mut self.fragment_buf, Vec::new());
+                        return Ok(Some(to_message(opcode, data)?));
+                    }
+                    // Not done yet; keep scanning the buffer for more frames.
+                }
+
+                OpCode::Text | OpCode::Binary => {
+                    if header.fin {
+                        if payload.len() > self.max_message_len {
+                            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                       format!("message of {} bytes exceeds the {} byte limit",
+                                                               payload.len(), self.max_message_len)));
+                        }
+                        return Ok(Some(to_message(header.opcode, payload)?));
+                    } else {
+                        if payload.len() > self.max_message_len {
+                            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                       format!("message of at least {} bytes exceeds the {} byte limit",
+                                                               payload.len(), self.max_message_len)));
+                        }
+                        self.fragment_opcode = Some(header.opcode);
+                        self.fragment_buf = payload;
+                        // Not done yet; keep scanning the buffer for more frames.
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn to_message(opcode: OpCode, data: Vec<u8>) -> io::Result<WsMessage> {
+    match opcode {
+        OpCode::Text => {
+            match String::from_utf8(data) {
+                Ok(s) => Ok(WsMessage::Text(s)),
+                Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            }
+        }
+        OpCode::Binary => Ok(WsMessage::Binary(data)),
+        _ => unreachable!("to_message only called for Text/Binary"),
+    }
+}
+
+impl Encoder for WsCodec {
+    type Item = WsMessage;
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: WsMessage, buf: &mut BytesMut) -> io::Result<()> {
+        let (opcode, payload): (OpCode, &[u8]) = match msg {
+            WsMessage::Text(ref s) => (OpCode::Text, s.as_bytes()),
+            WsMessage::Binary(ref b) => (OpCode::Binary, b),
+            WsMessage::Ping(ref b) => (OpCode::Ping, b),
+            WsMessage::Pong(ref b) => (OpCode::Pong, b),
+            WsMessage::Close => (OpCode::Close, b""),
+        };
+
+        buf.reserve(payload.len() + 10);
+        buf.put_u8(0x80 | opcode.to_byte());
+
+        if payload.len() <= 125 {
+            buf.put_u8(payload.len() as u8);
+        } else if payload.len() <= ::std::u16::MAX as usize {
+            buf.put_u8(126);
+            buf.put_u16_be(payload.len() as u16);
+        } else {
+            buf.put_u8(127);
+            buf.put_u64_be(payload.len() as u64);
+        }
+
+        buf.extend(payload);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{WsCodec, WsMessage};
+    use bytes::{BufMut, BytesMut};
+    use std::io;
+    use tokio_io::codec::{Encoder, Decoder};
+
+    #[test]
+    fn encoder_produces_unmasked_text_frame() {
+        let mut b = BytesMut::with_capacity(0);
+        let mut codec = WsCodec::new();
+        codec.encode(WsMessage::Text("hi".to_string()), &mut b).unwrap();
+        assert_eq!(&[0x81, 0x02, b'h', b'i'], &b[..]);
+    }
+
+    #[test]
+    fn decoder_unmasks_client_text_frame() {
+        let mut b = BytesMut::with_capacity(0);
+        // FIN + text opcode, masked, length 2
+        b.put(&[0x81u8, 0x82][..]);
+        let key = [0x01u8, 0x02, 0x03, 0x04];
+        b.put(&key[..]);
+        let payload = [b'h' ^ key[0], b'i' ^ key[1]];
+        b.put(&payload[..]);
+
+        let mut codec = WsCodec::new();
+        let msg = codec.decode(&mut b).unwrap().unwrap();
+        assert_eq!(WsMessage::Text("hi".to_string()), msg);
+    }
+
+    #[test]
+    fn decoder_waits_for_full_frame() {
+        let mut b = BytesMut::with_capacity(0);
+        b.put(&[0x81u8, 0x82][..]);
+        b.put(&[0x01u8, 0x02, 0x03, 0x04][..]);
+        b.put(&[b'h' ^ 0x01][..]);
+
+        let mut codec = WsCodec::new();
+        assert!(codec.decode(&mut b).unwrap().is_none());
+
+        b.put(&[b'i' ^ 0x02][..]);
+        let msg = codec.decode(&mut b).unwrap().unwrap();
+        assert_eq!(WsMessage::Text("hi".to_string()), msg);
+    }
+
+    #[test]
+    fn decoder_reassembles_fragmented_text_message() {
+        let mut b = BytesMut::with_capacity(0);
+        // First fragment: FIN=0, opcode=Text, unmasked, "he"
+        b.put(&[0x01u8, 0x02, b'h', b'e'][..]);
+        // Final fragment: FIN=1, opcode=Continuation, unmasked, "llo"
+        b.put(&[0x80u8, 0x03, b'l', b'l', b'o'][..]);
+
+        let mut codec = WsCodec::new();
+        let msg = codec.decode(&mut b).unwrap().unwrap();
+        assert_eq!(WsMessage::Text("hello".to_string()), msg);
+    }
+
+    #[test]
+    fn decoder_rejects_a_reassembled_message_over_the_limit() {
+        let mut b = BytesMut::with_capacity(0);
+        // First fragment: FIN=0, opcode=Text, unmasked, "he"
+        b.put(&[0x01u8, 0x02, b'h', b'e'][..]);
+        // Final fragment: FIN=1, opcode=Continuation, unmasked, "llo"
+        b.put(&[0x80u8, 0x03, b'l', b'l', b'o'][..]);
+
+        let mut codec = WsCodec::with_max_message_len(4);
+        let err = codec.decode(&mut b).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn decoder_passes_through_ping_and_close() {
+        let mut b = BytesMut::with_capacity(0);
+        b.put(&[0x89u8, 0x00][..]); // Ping, no payload
+        b.put(&[0x88u8, 0x00][..]); // Close, no payload
+
+        let mut codec = WsCodec::new();
+        assert_eq!(WsMessage::Ping(vec![]), codec.decode(&mut b).unwrap().unwrap());
+        assert_eq!(WsMessage::Close, codec.decode(&mut b).unwrap().unwrap());
+    }
+}